@@ -0,0 +1,21 @@
+use sqlx::PgPool;
+
+use crate::CrudError;
+
+/// Resolves a possibly-historical `vocabulary_id` to its canonical one, so a
+/// caller holding an id from before a dedup pass can still look it up via
+/// [`crate::query_dictionary`]. Returns `id` unchanged when it was never
+/// merged away.
+pub async fn resolve_canonical_id(db: &PgPool, id: i64) -> Result<i64, CrudError> {
+    let record = sqlx::query!(
+        r#"
+        SELECT canonical_id
+        FROM dictionary_aliases
+        WHERE alias_id = $1
+        "#,
+        id
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(record.map(|r| r.canonical_id).unwrap_or(id))
+}
@@ -0,0 +1,11 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CrudError {
+    #[error("failed to update dictionary data: {0}")]
+    UpdateData(#[source] anyhow::Error),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error("no dictionary version has completed yet")]
+    NoVersions,
+}
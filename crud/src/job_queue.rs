@@ -0,0 +1,239 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{update_data::DEFAULT_GIT_REF, update_dictionary, CrudError};
+
+/// How long a job may hold `running` with no heartbeat before it's assumed crashed.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often a claimed job's heartbeat is refreshed while it runs.
+const HEARTBEAT_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the worker polls for new jobs when the queue is empty.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the crash-recovery sweep runs.
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UpdateJob {
+    git_ref: String,
+}
+
+struct ClaimedJob {
+    id: Uuid,
+    job: serde_json::Value,
+}
+
+/// Enqueues a dictionary rebuild against `git_ref` and returns immediately
+/// with the new job's id.
+pub async fn enqueue_update(db: &PgPool, git_ref: &str) -> Result<Uuid, CrudError> {
+    let job = serde_json::to_value(UpdateJob {
+        git_ref: git_ref.to_string(),
+    })
+    .map_err(|e| CrudError::UpdateData(e.into()))?;
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO job_queue (job, status)
+        VALUES ($1, 'new')
+        RETURNING id
+        "#,
+        job
+    )
+    .fetch_one(db)
+    .await?;
+    info!("Enqueued dictionary rebuild job {}", record.id);
+    Ok(record.id)
+}
+
+/// Loops popping jobs off `job_queue` one at a time and executing
+/// `update_dictionary` for each, returning only if claiming or deleting a job
+/// fails with a database error. Also spawns a background sweep that requeues
+/// jobs whose worker crashed mid-run.
+pub async fn run_worker(db: PgPool) -> Result<(), CrudError> {
+    let recovery_db = db.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RECOVERY_INTERVAL).await;
+            if let Err(e) = requeue_stale_jobs(&recovery_db).await {
+                warn!("failed to requeue stale dictionary rebuild jobs: {e}");
+            }
+        }
+    });
+
+    loop {
+        match claim_next_job(&db).await? {
+            Some(claimed) => {
+                info!("Picked up dictionary rebuild job {}", claimed.id);
+                let git_ref = serde_json::from_value::<UpdateJob>(claimed.job)
+                    .map(|job| job.git_ref)
+                    .unwrap_or_else(|e| {
+                        warn!(
+                            "job {} has no valid payload, falling back to {}: {e}",
+                            claimed.id, DEFAULT_GIT_REF
+                        );
+                        DEFAULT_GIT_REF.to_string()
+                    });
+
+                let heartbeat_db = db.clone();
+                let heartbeat_id = claimed.id;
+                let heartbeat_task = tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(HEARTBEAT_RENEW_INTERVAL).await;
+                        if let Err(e) = renew_heartbeat(&heartbeat_db, heartbeat_id).await {
+                            warn!("failed to renew heartbeat for job {}: {e}", heartbeat_id);
+                        }
+                    }
+                });
+                let outcome = update_dictionary(&db, &git_ref).await;
+                heartbeat_task.abort();
+
+                match outcome {
+                    Ok(()) => delete_job(&db, claimed.id).await?,
+                    Err(e) => warn!("dictionary rebuild job {} failed: {e}", claimed.id),
+                }
+            }
+            None => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// Atomically claims one `new` job (the lowest by its random UUID id, so not
+/// necessarily the oldest), marking it `running` with a fresh heartbeat so
+/// concurrent workers never double-process it.
+async fn claim_next_job(db: &PgPool) -> Result<Option<ClaimedJob>, CrudError> {
+    let record = sqlx::query_as!(
+        ClaimedJob,
+        r#"
+        UPDATE job_queue
+        SET status = 'running', heartbeat = now()
+        WHERE id = (
+            SELECT id FROM job_queue
+            WHERE status = 'new'
+            ORDER BY id
+            FOR UPDATE SKIP LOCKED
+            LIMIT 1
+        )
+        RETURNING id, job
+        "#
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(record)
+}
+
+/// Requeues `running` jobs whose heartbeat has gone stale, e.g. because the
+/// worker that claimed them crashed before finishing.
+async fn requeue_stale_jobs(db: &PgPool) -> Result<(), CrudError> {
+    let cutoff: DateTime<Utc> =
+        Utc::now() - chrono::Duration::from_std(HEARTBEAT_TIMEOUT).unwrap();
+    let result = sqlx::query!(
+        r#"
+        UPDATE job_queue
+        SET status = 'new'
+        WHERE status = 'running' AND heartbeat < $1
+        "#,
+        cutoff
+    )
+    .execute(db)
+    .await?;
+    if result.rows_affected() > 0 {
+        info!(
+            "Requeued {} stale dictionary rebuild job(s)",
+            result.rows_affected()
+        );
+    }
+    Ok(())
+}
+
+/// Refreshes a still-`running` job's heartbeat so [`requeue_stale_jobs`]
+/// doesn't reclaim it out from under the worker that's actively processing it.
+async fn renew_heartbeat(db: &PgPool, id: Uuid) -> Result<(), CrudError> {
+    sqlx::query!(
+        "UPDATE job_queue SET heartbeat = now() WHERE id = $1 AND status = 'running'",
+        id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+async fn delete_job(db: &PgPool, id: Uuid) -> Result<(), CrudError> {
+    sqlx::query!("DELETE FROM job_queue WHERE id = $1", id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn job_status(db: &PgPool, id: Uuid) -> String {
+        sqlx::query!(r#"SELECT status::text AS "status!" FROM job_queue WHERE id = $1"#, id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .status
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_enqueue_update_and_delete_job(db: PgPool) {
+        let id = enqueue_update(&db, "main").await.unwrap();
+        assert_eq!("new", job_status(&db, id).await);
+
+        delete_job(&db, id).await.unwrap();
+        let remaining = sqlx::query!("SELECT id FROM job_queue WHERE id = $1", id)
+            .fetch_optional(&db)
+            .await
+            .unwrap();
+        assert!(remaining.is_none());
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_claim_next_job_is_exclusive(db: PgPool) {
+        let id = enqueue_update(&db, "main").await.unwrap();
+
+        let claimed = claim_next_job(&db).await.unwrap().unwrap();
+        assert_eq!(id, claimed.id);
+        assert_eq!("running", job_status(&db, id).await);
+
+        // Already running, so a concurrent worker finds nothing to pop.
+        assert!(claim_next_job(&db).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_requeue_stale_jobs(db: PgPool) {
+        let id = enqueue_update(&db, "main").await.unwrap();
+        claim_next_job(&db).await.unwrap().unwrap();
+
+        let stale: DateTime<Utc> =
+            Utc::now() - chrono::Duration::from_std(HEARTBEAT_TIMEOUT).unwrap() - chrono::Duration::seconds(1);
+        sqlx::query!("UPDATE job_queue SET heartbeat = $1 WHERE id = $2", stale, id)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        requeue_stale_jobs(&db).await.unwrap();
+        assert_eq!("new", job_status(&db, id).await);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_renew_heartbeat_keeps_job_from_being_requeued(db: PgPool) {
+        let id = enqueue_update(&db, "main").await.unwrap();
+        claim_next_job(&db).await.unwrap().unwrap();
+
+        let stale: DateTime<Utc> =
+            Utc::now() - chrono::Duration::from_std(HEARTBEAT_TIMEOUT).unwrap() - chrono::Duration::seconds(1);
+        sqlx::query!("UPDATE job_queue SET heartbeat = $1 WHERE id = $2", stale, id)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        renew_heartbeat(&db, id).await.unwrap();
+        requeue_stale_jobs(&db).await.unwrap();
+        assert_eq!("running", job_status(&db, id).await);
+    }
+}
@@ -1,20 +1,28 @@
 pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("../migrations");
 
+mod alias;
 mod error;
+mod job_queue;
+mod notify;
 mod query;
 mod update_data;
+mod version;
 
 use std::{str::FromStr, time::Duration};
 
 use anyhow::{Context, Ok};
+pub use alias::resolve_canonical_id;
 pub use error::CrudError;
+pub use job_queue::{enqueue_update, run_worker};
+pub use notify::subscribe_updates;
 pub use query::query_dictionary;
 use sqlx::{
     migrate::MigrateDatabase,
     postgres::{PgConnectOptions, PgPoolOptions},
     PgPool,
 };
-pub use update_data::update_dictionary;
+pub use update_data::{update_dictionary, DEFAULT_GIT_REF};
+pub use version::{list_versions, DictionaryVersion};
 
 mod test_data;
 use sqlx::ConnectOptions;
@@ -0,0 +1,63 @@
+use futures::{channel::mpsc, Stream};
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use sqlx::PgPool;
+use tokio_postgres::AsyncMessage;
+use tracing::{info, warn};
+
+use crate::CrudError;
+
+const CHANNEL: &str = "dictionary_updated";
+
+/// Issues `NOTIFY dictionary_updated` carrying `payload` (the new version id).
+pub(crate) async fn notify_updated(db: &PgPool, payload: &str) -> Result<(), CrudError> {
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(payload)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Opens a dedicated connection, issues `LISTEN dictionary_updated`, and
+/// yields each notification's payload as it arrives.
+pub async fn subscribe_updates(db_url: &str) -> Result<impl Stream<Item = String>, CrudError> {
+    // `establish_conn`'s sqlx pool negotiates TLS via `PgConnectOptions`;
+    // match that here instead of `NoTls` so this still connects against a
+    // Postgres that requires TLS.
+    let connector =
+        MakeTlsConnector::new(TlsConnector::new().map_err(|e| CrudError::UpdateData(e.into()))?);
+    let (client, mut connection) = tokio_postgres::connect(db_url, connector)
+        .await
+        .map_err(|e| CrudError::UpdateData(e.into()))?;
+
+    client
+        .batch_execute(&format!("LISTEN {CHANNEL}"))
+        .await
+        .map_err(|e| CrudError::UpdateData(e.into()))?;
+    info!("Subscribed to {} notifications", CHANNEL);
+
+    let (tx, rx) = mpsc::unbounded();
+    tokio::spawn(async move {
+        // Held here so the connection stays open as long as this task runs,
+        // not just until the caller returns.
+        let _client = client;
+        loop {
+            match futures::future::poll_fn(|cx| connection.poll_message(cx)).await {
+                Some(Ok(AsyncMessage::Notification(n))) => {
+                    if tx.unbounded_send(n.payload().to_string()).is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!("{} listener connection error: {e}", CHANNEL);
+                    break;
+                }
+                None => break,
+            }
+        }
+    });
+
+    Ok(rx)
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use model::Language;
+use sqlx::PgPool;
+
+use crate::{version::latest_version_id, CrudError};
+
+/// Looks up every dictionary entry, keyed by `vocabulary_id`.
+///
+/// `languages`, when `Some`, narrows each entry's translations to just
+/// those instead of all of `Language::iter()`. `version_id`, when `None`,
+/// reads from the newest completed [`crate::DictionaryVersion`] (see
+/// [`crate::list_versions`]) and fails with [`CrudError::NoVersions`] when
+/// none has completed.
+pub async fn query_dictionary(
+    db: &PgPool,
+    languages: Option<Vec<Language>>,
+    version_id: Option<i64>,
+) -> Result<HashMap<i64, HashMap<Language, String>>, CrudError> {
+    let version_id = match version_id {
+        Some(version_id) => version_id,
+        None => latest_version_id(db).await?.ok_or(CrudError::NoVersions)?,
+    };
+
+    let rows = match languages {
+        Some(languages) => {
+            sqlx::query!(
+                r#"
+                SELECT vocabulary_id, language AS "language!: Language", vocabulary_translation
+                FROM dictionary_items
+                WHERE version_id = $1 AND language = ANY($2)
+                "#,
+                version_id,
+                &languages as &[Language]
+            )
+            .fetch_all(db)
+            .await?
+        }
+        None => {
+            sqlx::query!(
+                r#"
+                SELECT vocabulary_id, language AS "language!: Language", vocabulary_translation
+                FROM dictionary_items
+                WHERE version_id = $1
+                "#,
+                version_id
+            )
+            .fetch_all(db)
+            .await?
+        }
+    };
+
+    let mut result: HashMap<i64, HashMap<Language, String>> = HashMap::new();
+    for row in rows {
+        result
+            .entry(row.vocabulary_id)
+            .or_default()
+            .insert(row.language, row.vocabulary_translation);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::version::{complete_version, create_version};
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_query_dictionary_filters_languages(db: PgPool) {
+        let version_id = create_version(&db, "main").await.unwrap();
+        complete_version(&db, version_id).await.unwrap();
+        sqlx::query!(
+            r#"
+            INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation", "version_id")
+            VALUES ($1, $2, $3, $5), ($1, $4, $6, $5)
+            "#,
+            1,
+            Language::Chs as Language,
+            "你好",
+            Language::En as Language,
+            version_id,
+            "Hello"
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let all = query_dictionary(&db, None, None).await.unwrap();
+        assert_eq!(2, all[&1].len());
+
+        let filtered = query_dictionary(&db, Some(vec![Language::En]), None)
+            .await
+            .unwrap();
+        assert_eq!(1, filtered[&1].len());
+        assert_eq!("Hello", filtered[&1][&Language::En]);
+    }
+}
@@ -1,126 +1,253 @@
-use std::{collections::HashMap, sync::Arc};
+use std::collections::HashMap;
 
-use futures::future::try_join_all;
 use indicatif::{ProgressBar, ProgressStyle};
-use itertools::Itertools;
-use lazy_static::lazy_static;
 
 use model::Language;
 use strum::IntoEnumIterator;
 
 use tracing::info;
 
-use sqlx::PgPool;
-
-use crate::CrudError;
-
-lazy_static! {
-    static ref LANGUAGE_URL_MAPPING: HashMap<Language, String> = {
-        Language::iter()
-            .map(|lang| {
-                let url = format!(
-                    "https://github.com/Masterain98/GenshinData/raw/main/TextMap/TextMap{}.json",
-                    lang.to_string().to_uppercase()
-                );
-                info!("Data url for {} is: {}", lang.to_string(), url);
-                (lang, url)
-            })
-            .collect()
-    };
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{
+    notify::notify_updated,
+    version::{complete_version, create_version, latest_version_id},
+    CrudError,
+};
+
+/// The `GenshinData` ref `update_dictionary` tracks when no explicit ref is
+/// requested, e.g. from the job queue worker.
+pub const DEFAULT_GIT_REF: &str = "main";
+
+fn language_url(lang: Language, git_ref: &str) -> String {
+    let url = format!(
+        "https://github.com/Masterain98/GenshinData/raw/{}/TextMap/TextMap{}.json",
+        git_ref,
+        lang.to_string().to_uppercase()
+    );
+    info!("Data url for {} is: {}", lang, url);
+    url
 }
 
-pub async fn update_dictionary(db: &PgPool) -> Result<(), CrudError> {
-    truncate_table(db).await?;
-    for (lang, url) in LANGUAGE_URL_MAPPING.iter() {
+pub async fn update_dictionary(db: &PgPool, git_ref: &str) -> Result<(), CrudError> {
+    let previous_version_id = latest_version_id(db).await?;
+    let version_id = create_version(db, git_ref).await?;
+    for lang in Language::iter() {
         info!("Getting data for {}", lang);
-        let map = reqwest::get(url)
+        let url = language_url(lang, git_ref);
+        let map = reqwest::get(&url)
             .await
             .map_err(|e| CrudError::UpdateData(e.into()))?
             .json::<HashMap<i64, String>>()
             .await
             .map_err(|e| CrudError::UpdateData(e.into()))?;
-        info!("Updating data for {}", lang);
-        let inserted_count = insert_items(*lang, map, db).await?;
-        info!("Insert {}", inserted_count);
+        info!("Syncing data for {}", lang);
+        sync_language(lang, &map, previous_version_id, version_id, db).await?;
     }
-    delete_duplicated_items(db).await?;
+    merge_equivalent_items(db, version_id).await?;
+    complete_version(db, version_id).await?;
+    notify_updated(db, &version_id.to_string()).await?;
     Ok(())
 }
 
-pub async fn insert_items(
+/// Snapshots one language's `TextMap{LANG}.json` into `version_id`, so each
+/// recorded version holds the complete dataset as of that rebuild — the
+/// rows from `previous_version_id` are left untouched, which is what makes
+/// `query_dictionary(db, _, Some(old_version))` able to diff or roll back to
+/// an older patch instead of always reading the latest state.
+async fn sync_language(
     lang: Language,
-    map: HashMap<i64, String>,
+    map: &HashMap<i64, String>,
+    previous_version_id: Option<i64>,
+    version_id: i64,
     db: &PgPool,
-) -> Result<usize, sqlx::Error> {
+) -> Result<(), CrudError> {
+    let mut tx = db.begin().await?;
+    let (inserted, updated) = upsert_items(lang, map, version_id, &mut tx).await?;
+    tx.commit().await?;
+    let removed = count_removed(lang, map, previous_version_id, db).await?;
+    info!(
+        "{}: inserted {}, updated {}, removed since previous version {}",
+        lang, inserted, updated, removed
+    );
+    Ok(())
+}
+
+/// Writes every `(vocabulary_id, language)` in `map` as a row tagged with
+/// `version_id`, returning the count of rows actually inserted and the
+/// count actually updated (only possible if this exact version is retried
+/// with changed source data; identical re-runs count as neither).
+async fn upsert_items(
+    lang: Language,
+    map: &HashMap<i64, String>,
+    version_id: i64,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(usize, usize), sqlx::Error> {
     let len = map.len();
-    let bar = Arc::new(ProgressBar::new(len as u64));
-    let queries = map.into_iter().map(|(voc_id, voc_trans)| {
-        let style = ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7}\n{msg}",
+    let bar = ProgressBar::new(len as u64);
+    let style = ProgressStyle::with_template(
+        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7}\n{msg}",
+    )
+    .unwrap()
+    .progress_chars("##-");
+    bar.set_style(style);
+
+    let mut inserted = 0;
+    let mut updated = 0;
+    for (voc_id, voc_trans) in map {
+        bar.set_message(format!(
+            "{} {}: {}",
+            lang,
+            voc_id,
+            voc_trans.chars().take(50).collect::<String>()
+        ));
+        let record = sqlx::query!(
+            r#"
+            INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation", "version_id")
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (vocabulary_id, language, version_id) DO UPDATE
+                SET vocabulary_translation = EXCLUDED.vocabulary_translation
+            WHERE dictionary_items.vocabulary_translation IS DISTINCT FROM EXCLUDED.vocabulary_translation
+            RETURNING (xmax = 0) AS "inserted!"
+            "#,
+            voc_id,
+            lang as Language,
+            voc_trans,
+            version_id
         )
-        .unwrap()
-        .progress_chars("##-");
-        let bar = bar.clone();
-        bar.set_style(style);
-        async move {
-            bar.set_message(format!("{} {}: {}", lang, voc_id, voc_trans.chars().take(50).collect::<String>()));
-            let result = sqlx::query!(
-                r#"
-                INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation")
-                VALUES ($1, $2, $3)
-                "#,
-                voc_id,
-                lang as Language,
-                voc_trans
-            )
-            .execute(db)
-            .await;
-            bar.inc(1);
-            result
+        .fetch_optional(&mut **tx)
+        .await?;
+        match record {
+            Some(r) if r.inserted => inserted += 1,
+            Some(_) => updated += 1,
+            None => {}
         }
-    });
-    for chunk in queries.chunks(50).into_iter() {
-        try_join_all(chunk).await?;
+        bar.inc(1);
     }
     bar.finish();
-    Ok(len)
+    Ok((inserted, updated))
 }
 
-async fn delete_duplicated_items(db: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query!(
+/// Counts, purely for logging, how many ids present in `previous_version_id`
+/// for `lang` are absent from `map` — i.e. ids this rebuild dropped from the
+/// live dataset. Nothing is deleted: `previous_version_id`'s rows are left
+/// alone so that version stays queryable.
+async fn count_removed(
+    lang: Language,
+    map: &HashMap<i64, String>,
+    previous_version_id: Option<i64>,
+    db: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let Some(previous_version_id) = previous_version_id else {
+        return Ok(0);
+    };
+    let ids: Vec<i64> = map.keys().copied().collect();
+    let record = sqlx::query!(
         r#"
-        DELETE FROM dictionary_items
-        WHERE
-            vocabulary_id NOT IN (
-                SELECT MIN(vocabulary_id)
-                FROM (
-                        SELECT vocabulary_id, STRING_AGG(vocabulary_translation, ', ' ORDER BY language) AS translations
-                        FROM (
-                                SELECT
-                                    vocabulary_id, vocabulary_translation, language
-                                FROM dictionary_items
-                            ) AS sorted_items
-                        GROUP BY
-                            vocabulary_id
-                    ) AS subquery_alias
-                GROUP BY
-                    translations
-            )
-        "#
+        SELECT COUNT(*) AS "count!"
+        FROM dictionary_items
+        WHERE language = $1 AND version_id = $2 AND vocabulary_id != ALL($3)
+        "#,
+        lang as Language,
+        previous_version_id,
+        &ids
     )
-    .execute(db)
+    .fetch_one(db)
     .await?;
-    Ok(())
+    Ok(record.count as u64)
 }
 
-async fn truncate_table(db: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query!(
+/// Trims and case-folds a pivot-language translation for equivalence
+/// comparison.
+fn normalize(translation: &str) -> String {
+    translation.trim().to_lowercase()
+}
+
+/// Groups vocabulary ids within `version_id` that share the same normalized
+/// (CHS, EN) translation pair, keeps the lowest id per group as canonical,
+/// and records the rest in `dictionary_aliases` instead of hard-deleting
+/// them — so an id merged away in a past rebuild can still be resolved back
+/// to its canonical entry via [`crate::alias::resolve_canonical_id`]. Ids
+/// missing one of the pivot languages are never grouped with ids that have
+/// it, and ids missing both (or with both pivots blank) are left alone
+/// entirely. Scoped to `version_id` so merging never touches the rows of an
+/// older, still-queryable version.
+async fn merge_equivalent_items(db: &PgPool, version_id: i64) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query!(
         r#"
-        TRUNCATE TABLE dictionary_items
-        "#
+        SELECT
+            vocabulary_id,
+            MAX(vocabulary_translation) FILTER (WHERE language = $1) AS chs,
+            MAX(vocabulary_translation) FILTER (WHERE language = $2) AS en
+        FROM dictionary_items
+        WHERE version_id = $3
+        GROUP BY vocabulary_id
+        "#,
+        Language::Chs as Language,
+        Language::En as Language,
+        version_id
     )
-    .execute(db)
+    .fetch_all(db)
     .await?;
+
+    let mut groups: HashMap<String, Vec<i64>> = HashMap::new();
+    for row in rows {
+        let chs_norm = row.chs.as_deref().map(normalize).unwrap_or_default();
+        let en_norm = row.en.as_deref().map(normalize).unwrap_or_default();
+        let key = if chs_norm.is_empty() && en_norm.is_empty() {
+            // Either genuinely missing both pivots, or present but blank —
+            // either way there's nothing to compare, so don't group these
+            // ids with every other blank-pivot id in the batch.
+            format!("__no_pivot__{}", row.vocabulary_id)
+        } else {
+            format!("{chs_norm}\u{0}{en_norm}")
+        };
+        groups.entry(key).or_default().push(row.vocabulary_id);
+    }
+
+    let mut merged = 0;
+    let mut tx = db.begin().await?;
+    for ids in groups.into_values() {
+        if ids.len() < 2 {
+            continue;
+        }
+        let canonical_id = *ids.iter().min().unwrap();
+        for alias_id in ids.into_iter().filter(|id| *id != canonical_id) {
+            sqlx::query!(
+                r#"
+                INSERT INTO dictionary_aliases (alias_id, canonical_id)
+                VALUES ($1, $2)
+                ON CONFLICT (alias_id) DO UPDATE SET canonical_id = EXCLUDED.canonical_id
+                "#,
+                alias_id,
+                canonical_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            // `alias_id` may itself have been a canonical id for ids merged
+            // away in an earlier rebuild — repoint those so the whole chain
+            // still resolves to the new canonical id.
+            sqlx::query!(
+                "UPDATE dictionary_aliases SET canonical_id = $1 WHERE canonical_id = $2",
+                canonical_id,
+                alias_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "DELETE FROM dictionary_items WHERE vocabulary_id = $1 AND version_id = $2",
+                alias_id,
+                version_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            merged += 1;
+        }
+    }
+    tx.commit().await?;
+    if merged > 0 {
+        info!("Merged {} duplicate vocabulary id(s) into aliases", merged);
+    }
     Ok(())
 }
 
@@ -131,8 +258,9 @@ mod test {
     #[tokio::test]
     async fn test_content_access() {
         let client = reqwest::Client::new();
-        for (_lang, url) in LANGUAGE_URL_MAPPING.iter() {
-            let res = client.head(url).send().await.unwrap();
+        for lang in Language::iter() {
+            let url = language_url(lang, DEFAULT_GIT_REF);
+            let res = client.head(&url).send().await.unwrap();
             assert_ne!(
                 res.headers()
                     .get("content-length")
@@ -147,81 +275,265 @@ mod test {
     }
 
     #[sqlx::test(migrator = "crate::MIGRATOR")]
-    async fn test_truncate_table(db: PgPool) {
-        sqlx::query!(
-            r#"
-            INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation")
-            VALUES ($1, $2, $3)
-            "#,
-            1,
-            Language::Chs as Language,
-            "Hello World"
+    async fn test_merge_equivalent_items(db: PgPool) {
+        let version_id = create_version(&db, "main").await.unwrap();
+        for (voc_id, lang, text) in [
+            (1, Language::Chs, "你好"),
+            (1, Language::En, "Hello"),
+            (2, Language::Chs, " 你好 "),
+            (2, Language::En, "HELLO"),
+        ] {
+            sqlx::query!(
+                r#"
+                INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation", "version_id")
+                VALUES ($1, $2, $3, $4)
+                "#,
+                voc_id,
+                lang as Language,
+                text,
+                version_id
+            )
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        merge_equivalent_items(&db, version_id).await.unwrap();
+
+        let remaining_ids: Vec<i64> = sqlx::query!(r#"SELECT DISTINCT vocabulary_id FROM dictionary_items"#)
+            .fetch_all(&db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.vocabulary_id)
+            .collect();
+        assert_eq!(vec![1], remaining_ids);
+
+        let alias = sqlx::query!(
+            r#"SELECT canonical_id FROM dictionary_aliases WHERE alias_id = $1"#,
+            2
         )
-        .execute(&db)
+        .fetch_one(&db)
         .await
         .unwrap();
-        truncate_table(&db).await.unwrap();
-        assert!(sqlx::query!(
-            r#"
-            SELECT "vocabulary_id", "language" AS "language!: Language", "vocabulary_translation"
-            FROM dictionary_items
-            "#
+        assert_eq!(1, alias.canonical_id);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_merge_equivalent_items_repoints_existing_alias_chains(db: PgPool) {
+        // First rebuild: ids 2 and 5 collapse, 2 stays canonical.
+        let v1 = create_version(&db, "v1").await.unwrap();
+        for voc_id in [2, 5] {
+            sqlx::query!(
+                "INSERT INTO dictionary_items (vocabulary_id, language, vocabulary_translation, version_id) VALUES ($1, $2, $3, $4)",
+                voc_id,
+                Language::Chs as Language,
+                "你好",
+                v1
+            )
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        merge_equivalent_items(&db, v1).await.unwrap();
+
+        // Second rebuild: upstream still has 2 and 5, plus a new id 1 with
+        // the same translation. 1 stays canonical; the existing (5 -> 2)
+        // alias must be repointed to (5 -> 1).
+        let v2 = create_version(&db, "v2").await.unwrap();
+        for voc_id in [1, 2, 5] {
+            sqlx::query!(
+                "INSERT INTO dictionary_items (vocabulary_id, language, vocabulary_translation, version_id) VALUES ($1, $2, $3, $4)",
+                voc_id,
+                Language::Chs as Language,
+                "你好",
+                v2
+            )
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        merge_equivalent_items(&db, v2).await.unwrap();
+
+        let canonical_for_5 = sqlx::query!(
+            "SELECT canonical_id FROM dictionary_aliases WHERE alias_id = $1",
+            5
         )
-        .fetch_optional(&db)
+        .fetch_one(&db)
         .await
         .unwrap()
-        .is_none());
+        .canonical_id;
+        assert_eq!(1, canonical_for_5);
     }
 
     #[sqlx::test(migrator = "crate::MIGRATOR")]
-    async fn test_delete_duplicate(db: PgPool) {
+    async fn test_merge_equivalent_items_keeps_ids_missing_a_pivot_language(db: PgPool) {
+        let version_id = create_version(&db, "main").await.unwrap();
+        // Id 1 has only CHS and id 2 has neither pivot, so they must never
+        // be merged despite both having an empty EN key.
         sqlx::query!(
             r#"
-            INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation")
-            VALUES ($1, $2, $3)
+            INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation", "version_id")
+            VALUES ($1, $2, $3, $4)
             "#,
             1,
             Language::Chs as Language,
-            "Hello World"
+            "你好",
+            version_id
         )
         .execute(&db)
         .await
         .unwrap();
         sqlx::query!(
             r#"
-            INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation")
-            VALUES ($1, $2, $3)
+            INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation", "version_id")
+            VALUES ($1, $2, $3, $4)
             "#,
             2,
-            Language::Chs as Language,
-            "Hello World"
+            Language::Jp as Language,
+            "こんにちは",
+            version_id
         )
         .execute(&db)
         .await
         .unwrap();
 
-        delete_duplicated_items(&db).await.unwrap();
+        merge_equivalent_items(&db, version_id).await.unwrap();
 
-        assert_eq!(
-            sqlx::query!(
-                r#"
-            SELECT "vocabulary_id", "language" AS "language!: Language", "vocabulary_translation"
-            FROM dictionary_items
-            "#
-            )
+        let remaining_ids: Vec<i64> = sqlx::query!(r#"SELECT DISTINCT vocabulary_id FROM dictionary_items ORDER BY vocabulary_id"#)
             .fetch_all(&db)
             .await
             .unwrap()
-            .len(),
-            1
-        );
+            .into_iter()
+            .map(|r| r.vocabulary_id)
+            .collect();
+        assert_eq!(vec![1, 2], remaining_ids);
     }
 
     #[sqlx::test(migrator = "crate::MIGRATOR")]
-    async fn test_insert_data(db: PgPool) {
+    async fn test_merge_equivalent_items_ignores_blank_pivot_translations(db: PgPool) {
+        let version_id = create_version(&db, "main").await.unwrap();
+        // Two unrelated placeholder ids, both with blank CHS+EN text — they
+        // must not be treated as equivalent just because both normalized
+        // keys happen to be the empty string.
+        for voc_id in [1, 2] {
+            for (lang, text) in [(Language::Chs, ""), (Language::En, "  ")] {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO "dictionary_items" ("vocabulary_id", "language", "vocabulary_translation", "version_id")
+                    VALUES ($1, $2, $3, $4)
+                    "#,
+                    voc_id,
+                    lang as Language,
+                    text,
+                    version_id
+                )
+                .execute(&db)
+                .await
+                .unwrap();
+            }
+        }
+
+        merge_equivalent_items(&db, version_id).await.unwrap();
+
+        let remaining_ids: Vec<i64> = sqlx::query!(r#"SELECT DISTINCT vocabulary_id FROM dictionary_items ORDER BY vocabulary_id"#)
+            .fetch_all(&db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.vocabulary_id)
+            .collect();
+        assert_eq!(vec![1, 2], remaining_ids);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_upsert_items(db: PgPool) {
         let data = include_bytes!("../test_data/TextMapCHS.json");
         let map: HashMap<i64, String> = serde_json::from_slice(data).unwrap();
-        let len = insert_items(Language::Chs, map, &db).await.unwrap();
-        assert_eq!(29, len);
+        let version_id = create_version(&db, "main").await.unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        let (inserted, updated) = upsert_items(Language::Chs, &map, version_id, &mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(29, inserted);
+        assert_eq!(0, updated);
+
+        // Re-running against an unchanged map at the same version should upsert nothing.
+        let mut tx = db.begin().await.unwrap();
+        let (inserted, updated) = upsert_items(Language::Chs, &map, version_id, &mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(0, inserted);
+        assert_eq!(0, updated);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_upsert_items_preserves_prior_versions(db: PgPool) {
+        let v1 = create_version(&db, "v1").await.unwrap();
+        let mut map = HashMap::new();
+        map.insert(1, "Hello".to_string());
+        map.insert(2, "World".to_string());
+
+        let mut tx = db.begin().await.unwrap();
+        upsert_items(Language::Chs, &map, v1, &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        // Next rebuild drops id 2 and only snapshots id 1 into the new version.
+        let v2 = create_version(&db, "v2").await.unwrap();
+        map.remove(&2);
+        let mut tx = db.begin().await.unwrap();
+        upsert_items(Language::Chs, &map, v2, &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let v1_ids: Vec<i64> = sqlx::query!(
+            "SELECT vocabulary_id FROM dictionary_items WHERE version_id = $1 ORDER BY vocabulary_id",
+            v1
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.vocabulary_id)
+        .collect();
+        assert_eq!(vec![1, 2], v1_ids);
+
+        let v2_ids: Vec<i64> = sqlx::query!(
+            "SELECT vocabulary_id FROM dictionary_items WHERE version_id = $1 ORDER BY vocabulary_id",
+            v2
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.vocabulary_id)
+        .collect();
+        assert_eq!(vec![1], v2_ids);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn test_count_removed(db: PgPool) {
+        let v1 = create_version(&db, "v1").await.unwrap();
+        let mut map = HashMap::new();
+        map.insert(1, "Hello".to_string());
+        map.insert(2, "World".to_string());
+
+        let mut tx = db.begin().await.unwrap();
+        upsert_items(Language::Chs, &map, v1, &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        map.remove(&2);
+        assert_eq!(
+            1,
+            count_removed(Language::Chs, &map, Some(v1), &db)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            0,
+            count_removed(Language::Chs, &map, None, &db).await.unwrap()
+        );
     }
 }
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::CrudError;
+
+/// A single recorded dictionary snapshot — one row per `update_dictionary`
+/// run, tagged with the `GenshinData` ref (or game version) it was built
+/// from so terminology can be diffed or rolled back across patches.
+#[derive(Debug, Clone, Serialize)]
+pub struct DictionaryVersion {
+    pub id: i64,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Records a new, not-yet-complete snapshot tagged with `label` (e.g.
+/// `"main"`, a commit SHA, or a game version string) and returns its id.
+/// Invisible to [`list_versions`]/`latest_version_id` until
+/// [`complete_version`] marks it done, so readers never see a version
+/// that's still mid-sync.
+pub(crate) async fn create_version(db: &PgPool, label: &str) -> Result<i64, CrudError> {
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO dictionary_versions (label)
+        VALUES ($1)
+        RETURNING id
+        "#,
+        label
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(record.id)
+}
+
+/// Marks a version as fully synced, making it visible to `latest_version_id`
+/// and [`list_versions`].
+pub(crate) async fn complete_version(db: &PgPool, version_id: i64) -> Result<(), CrudError> {
+    sqlx::query!(
+        "UPDATE dictionary_versions SET completed_at = now() WHERE id = $1",
+        version_id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Lists every completed snapshot, most recent first.
+pub async fn list_versions(db: &PgPool) -> Result<Vec<DictionaryVersion>, CrudError> {
+    let versions = sqlx::query_as!(
+        DictionaryVersion,
+        r#"
+        SELECT id, label, created_at
+        FROM dictionary_versions
+        WHERE completed_at IS NOT NULL
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(versions)
+}
+
+/// Returns the id of the newest (by `created_at`) completed snapshot, i.e. the one
+/// `query_dictionary` reads from when no explicit version is requested.
+/// `None` when no version has completed yet.
+pub(crate) async fn latest_version_id(db: &PgPool) -> Result<Option<i64>, CrudError> {
+    let record = sqlx::query!(
+        r#"
+        SELECT id
+        FROM dictionary_versions
+        WHERE completed_at IS NOT NULL
+        ORDER BY created_at DESC
+        LIMIT 1
+        "#
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(record.map(|r| r.id))
+}